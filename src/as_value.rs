@@ -1,17 +1,52 @@
-use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::error;
 use std::fmt;
 
+use indexmap::IndexMap;
 use serde::{
     ser::{self, Error as SerdeError},
     Serialize,
 };
 
+use super::shared;
+use super::typed;
 use super::value::{self, ToHessian, Value};
 
 // AsHessian Serializer
 #[derive(Clone, Default)]
-struct Serializer {}
+struct Serializer {
+    // `Some` once ref-tracking is turned on: every `shared::Shared` pointer
+    // seen so far, in the order its ref index was assigned. `None` keeps
+    // callers that never wrap anything in `Shared` at zero overhead.
+    //
+    // Tracking is keyed by pointer identity rather than structural equality,
+    // and an index is reserved *before* recursing into the pointee, so a
+    // true `Rc` cycle built through `Shared` is recognized and turned into a
+    // `Value::Ref` the moment it loops back, instead of recursing forever.
+    refs: Option<Vec<usize>>,
+}
+
+impl Serializer {
+    fn with_refs() -> Self {
+        Serializer {
+            refs: Some(Vec::new()),
+        }
+    }
+
+    fn ref_index_of(&self, ptr: usize) -> Option<usize> {
+        self.refs.as_ref()?.iter().position(|&seen| seen == ptr)
+    }
+
+    /// Reserves the next ref index for `ptr`. Returns `None` when
+    /// ref-tracking is disabled, in which case `Shared` values are never
+    /// deduped or cycle-checked.
+    fn register_ref(&mut self, ptr: usize) -> Option<usize> {
+        let refs = self.refs.as_mut()?;
+        let index = refs.len();
+        refs.push(ptr);
+        Some(index)
+    }
+}
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Error {
@@ -39,16 +74,19 @@ impl error::Error for Error {
 }
 
 struct SeqSerializer<'a> {
+    ser: &'a mut Serializer,
     name: Option<&'a str>,
     items: Vec<Value>,
 }
 
-struct MapSerializer {
+struct MapSerializer<'a> {
+    ser: &'a mut Serializer,
     keys: Vec<Value>,
     values: Vec<Value>,
 }
 
 struct StructSerializer<'a> {
+    ser: &'a mut Serializer,
     name: &'a str,
     fields: Vec<String>,
     values: Vec<Value>,
@@ -60,17 +98,16 @@ impl<'a> ser::SerializeSeq for SeqSerializer<'a> {
 
     #[inline]
     fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
-        self.items
-            .push(value.serialize(&mut Serializer::default())?);
+        self.items.push(value.serialize(&mut *self.ser)?);
         Ok(())
     }
 
     #[inline]
     fn end(self) -> Result<Value, Error> {
-        match self.name {
-            Some(name) => Ok(Value::List(value::List::from((name, self.items)))),
-            None => Ok(Value::List(value::List::from(self.items))),
-        }
+        Ok(match self.name {
+            Some(name) => Value::List(value::List::from((name, self.items))),
+            None => Value::List(value::List::from(self.items)),
+        })
     }
 }
 
@@ -119,26 +156,169 @@ impl<'a> ser::SerializeTupleVariant for SeqSerializer<'a> {
     }
 }
 
-impl<'a> ser::SerializeMap for MapSerializer {
+/// Collects the two fields of a `typed::Typed<T>` sentinel: the type name,
+/// then the value it tags. `end` re-wraps the already-built `Value` with
+/// that name rather than encoding the sentinel enum itself.
+struct TypedSerializer<'a> {
+    ser: &'a mut Serializer,
+    type_name: Option<String>,
+    value: Option<Value>,
+}
+
+impl<'a> ser::SerializeTupleVariant for TypedSerializer<'a> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        if self.type_name.is_none() {
+            match value.serialize(&mut *self.ser)? {
+                Value::String(name) => {
+                    self.type_name = Some(name);
+                    Ok(())
+                }
+                other => Err(Error {
+                    message: format!("Typed(..) expects a string type name, got {:?}", other),
+                }),
+            }
+        } else {
+            self.value = Some(value.serialize(&mut *self.ser)?);
+            Ok(())
+        }
+    }
+
+    fn end(self) -> Result<Value, Self::Error> {
+        let name = self.type_name.ok_or_else(|| Error {
+            message: "Typed(..) is missing its type name field".into(),
+        })?;
+        let value = self.value.ok_or_else(|| Error {
+            message: "Typed(..) is missing its wrapped value field".into(),
+        })?;
+        let typed = match value {
+            Value::List(value::List::Untyped(items)) => {
+                Value::List(value::List::from((name.as_str(), items)))
+            }
+            Value::List(value::List::Typed(_, items)) => {
+                Value::List(value::List::from((name.as_str(), items)))
+            }
+            Value::Map(m) => {
+                let mut owned = IndexMap::new();
+                for (k, v) in m {
+                    owned.insert(k, v);
+                }
+                Value::Map(value::Map::from((name.as_str(), owned)))
+            }
+            other => {
+                return Err(Error {
+                    message: format!(
+                        "Typed(\"{}\", ..) can only wrap a list, map, or struct, not {:?}",
+                        name, other
+                    ),
+                })
+            }
+        };
+        Ok(typed)
+    }
+}
+
+/// Collects the two fields of a `shared::Shared<T>` sentinel: the pointer
+/// address, then the pointee. Unlike `TypedSerializer`, the decision of
+/// whether to recurse into the pointee happens on the *first* field: if the
+/// pointer was already registered, the second field is never serialized at
+/// all, which is what keeps a true `Rc` cycle from recursing forever.
+struct SharedSerializer<'a> {
+    ser: &'a mut Serializer,
+    ptr: Option<usize>,
+    result: Option<Value>,
+}
+
+impl<'a> ser::SerializeTupleVariant for SharedSerializer<'a> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        if self.ptr.is_none() {
+            match value.serialize(&mut *self.ser)? {
+                Value::Long(addr) => {
+                    let ptr = addr as u64 as usize;
+                    if let Some(index) = self.ser.ref_index_of(ptr) {
+                        self.result = Some(Value::Ref(index as u32));
+                    } else {
+                        self.ser.register_ref(ptr);
+                    }
+                    self.ptr = Some(ptr);
+                    Ok(())
+                }
+                other => Err(Error {
+                    message: format!("Shared(..) expects a pointer address, got {:?}", other),
+                }),
+            }
+        } else if self.result.is_none() {
+            self.result = Some(value.serialize(&mut *self.ser)?);
+            Ok(())
+        } else {
+            // The pointer was already seen; the pointee is intentionally
+            // left unserialized so a cycle through this same `Shared` node
+            // doesn't recurse forever.
+            Ok(())
+        }
+    }
+
+    fn end(self) -> Result<Value, Self::Error> {
+        self.result.ok_or_else(|| Error {
+            message: "Shared(..) is missing its wrapped value field".into(),
+        })
+    }
+}
+
+/// Either a plain tuple-variant payload, a `typed::Typed<T>` sentinel, or a
+/// `shared::Shared<T>` sentinel — whichever `serialize_tuple_variant`
+/// intercepted.
+enum TupleVariantSerializer<'a> {
+    Seq(SeqSerializer<'a>),
+    Typed(TypedSerializer<'a>),
+    Shared(SharedSerializer<'a>),
+}
+
+impl<'a> ser::SerializeTupleVariant for TupleVariantSerializer<'a> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        match self {
+            TupleVariantSerializer::Seq(s) => ser::SerializeTupleVariant::serialize_field(s, value),
+            TupleVariantSerializer::Typed(s) => s.serialize_field(value),
+            TupleVariantSerializer::Shared(s) => s.serialize_field(value),
+        }
+    }
+
+    fn end(self) -> Result<Value, Self::Error> {
+        match self {
+            TupleVariantSerializer::Seq(s) => ser::SerializeTupleVariant::end(s),
+            TupleVariantSerializer::Typed(s) => s.end(),
+            TupleVariantSerializer::Shared(s) => s.end(),
+        }
+    }
+}
+
+impl<'a> ser::SerializeMap for MapSerializer<'a> {
     type Ok = Value;
     type Error = Error;
 
     #[inline]
     fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Self::Error> {
-        self.keys.push(key.serialize(&mut Serializer::default())?);
+        self.keys.push(key.serialize(&mut *self.ser)?);
         Ok(())
     }
 
     #[inline]
     fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
-        self.values
-            .push(value.serialize(&mut Serializer::default())?);
+        self.values.push(value.serialize(&mut *self.ser)?);
         Ok(())
     }
 
     #[inline]
     fn end(self) -> Result<Self::Ok, Self::Error> {
-        let mut map = HashMap::new();
+        let mut map = IndexMap::new();
         for (k, v) in self.keys.iter().zip(self.values.iter()) {
             map.insert(k.clone(), v.clone());
         }
@@ -146,7 +326,13 @@ impl<'a> ser::SerializeMap for MapSerializer {
     }
 }
 
-// TODO: Add struct type for Value
+// `ser.rs`'s streaming serializer needs a class-definition table because the
+// Hessian wire format dedups repeated `'C'` headers by index — that's a
+// byte-level economy with no analogue here. `Value` is an in-memory tree, not
+// a wire mirror: each struct's name and field layout is carried directly on
+// its `Map` node, so two distinct Rust types that happen to share a wire name
+// simply produce two independent named `Map`s with no shared table to
+// corrupt. That's why this impl has no class cache of its own.
 impl<'a> ser::SerializeStruct for StructSerializer<'a> {
     type Ok = Value;
     type Error = Error;
@@ -157,14 +343,13 @@ impl<'a> ser::SerializeStruct for StructSerializer<'a> {
         value: &T,
     ) -> Result<(), Self::Error> {
         self.fields.push(key.into());
-        self.values
-            .push(value.serialize(&mut Serializer::default())?);
+        self.values.push(value.serialize(&mut *self.ser)?);
         Ok(())
     }
 
     #[inline]
     fn end(self) -> Result<Value, Self::Error> {
-        let mut map = HashMap::new();
+        let mut map = IndexMap::new();
         for (k, v) in self.fields.iter().zip(self.values.iter()) {
             map.insert(k.to_hessian(), v.clone());
         }
@@ -198,8 +383,8 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     type SerializeSeq = SeqSerializer<'a>;
     type SerializeTuple = Self::SerializeSeq;
     type SerializeTupleStruct = Self::SerializeTuple;
-    type SerializeTupleVariant = Self::SerializeTuple;
-    type SerializeMap = MapSerializer;
+    type SerializeTupleVariant = TupleVariantSerializer<'a>;
+    type SerializeMap = MapSerializer<'a>;
     type SerializeStruct = StructSerializer<'a>;
     type SerializeStructVariant = Self::SerializeStruct;
 
@@ -228,6 +413,24 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         Ok(value.to_hessian())
     }
 
+    // Encode-side widening only: Hessian has no 128-bit wire type, so this
+    // fits in a Long (or fails) when serializing. This is NOT mirrored on
+    // decode: `Deserializer::deserialize_i128`/`deserialize_u128` still fall
+    // back to serde's default, which hard-errors regardless of whether the
+    // underlying Long actually fits — so a Long read back into an i128/u128
+    // field fails today even when it's well within range. Closing that gap
+    // means implementing those two methods on the deserializer; tracked as
+    // follow-up, not done here.
+    #[inline]
+    fn serialize_i128(self, value: i128) -> Result<Self::Ok, Self::Error> {
+        match i64::try_from(value) {
+            Ok(value) => self.serialize_i64(value),
+            Err(_) => Err(Error {
+                message: format!("i128 value {} does not fit in a Hessian Long", value),
+            }),
+        }
+    }
+
     #[inline]
     fn serialize_u8(self, value: u8) -> Result<Self::Ok, Self::Error> {
         Ok((value as i32).to_hessian())
@@ -252,6 +455,16 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         Ok((value as i64).to_hessian())
     }
 
+    #[inline]
+    fn serialize_u128(self, value: u128) -> Result<Self::Ok, Self::Error> {
+        match i64::try_from(value) {
+            Ok(value) => self.serialize_i64(value),
+            Err(_) => Err(Error {
+                message: format!("u128 value {} does not fit in a Hessian Long", value),
+            }),
+        }
+    }
+
     #[inline]
     fn serialize_f32(self, value: f32) -> Result<Self::Ok, Self::Error> {
         Ok((value as f64).to_hessian())
@@ -332,10 +545,12 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
         match len {
             Some(len) => Ok(SeqSerializer {
+                ser: self,
                 name: None,
                 items: Vec::with_capacity(len),
             }),
             _ => Ok(SeqSerializer {
+                ser: self,
                 name: None,
                 items: Vec::new(),
             }),
@@ -345,6 +560,7 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     #[inline]
     fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Self::Error> {
         Ok(SeqSerializer {
+            ser: self,
             name: None,
             items: Vec::with_capacity(len),
         })
@@ -357,6 +573,7 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         len: usize,
     ) -> Result<Self::SerializeTupleStruct, Self::Error> {
         Ok(SeqSerializer {
+            ser: self,
             name: Some(name),
             items: Vec::with_capacity(len),
         })
@@ -365,25 +582,42 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     #[inline]
     fn serialize_tuple_variant(
         self,
-        _name: &'static str,
+        name: &'static str,
         _variant_index: u32,
         variant: &'static str,
         len: usize,
     ) -> Result<Self::SerializeTupleVariant, Self::Error> {
-        Ok(SeqSerializer {
-            name: Some(variant),
-            items: Vec::with_capacity(len),
-        })
+        if name == typed::ENUM_NAME && variant == typed::VARIANT_NAME && len == 2 {
+            Ok(TupleVariantSerializer::Typed(TypedSerializer {
+                ser: self,
+                type_name: None,
+                value: None,
+            }))
+        } else if name == shared::ENUM_NAME && variant == shared::VARIANT_NAME && len == 2 {
+            Ok(TupleVariantSerializer::Shared(SharedSerializer {
+                ser: self,
+                ptr: None,
+                result: None,
+            }))
+        } else {
+            Ok(TupleVariantSerializer::Seq(SeqSerializer {
+                ser: self,
+                name: Some(variant),
+                items: Vec::with_capacity(len),
+            }))
+        }
     }
 
     #[inline]
     fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
         match len {
             Some(len) => Ok(MapSerializer {
+                ser: self,
                 keys: Vec::with_capacity(len),
                 values: Vec::with_capacity(len),
             }),
             None => Ok(MapSerializer {
+                ser: self,
                 keys: Vec::new(),
                 values: Vec::new(),
             }),
@@ -397,6 +631,7 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         len: usize,
     ) -> Result<Self::SerializeStruct, Self::Error> {
         Ok(StructSerializer {
+            ser: self,
             name,
             fields: Vec::with_capacity(len),
             values: Vec::with_capacity(len),
@@ -412,6 +647,7 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         len: usize,
     ) -> Result<Self::SerializeStructVariant, Self::Error> {
         Ok(StructSerializer {
+            ser: self,
             name: variant,
             fields: Vec::with_capacity(len),
             values: Vec::with_capacity(len),
@@ -433,26 +669,31 @@ impl serde::Serialize for Value {
             Value::Bytes(ref bytes) => serializer.serialize_bytes(bytes),
             Value::String(ref s) => serializer.serialize_str(s),
             Value::Ref(i) => serializer.serialize_i32(i as i32),
-            Value::List(ref l) => {
-                match *l {
-                    value::List::Typed(name, v) => {
-                    let ser = serializer.serialize_seq(Some(v.len()))?;
+            Value::List(ref l) => match *l {
+                value::List::Typed(name, ref v) => {
+                    use ser::SerializeTupleStruct;
+                    let mut seq = serializer.serialize_tuple_struct(name, v.len())?;
                     for e in v {
-                        ser.serialize_element(e)?;
+                        seq.serialize_field(e)?;
                     }
                     seq.end()
-                    }
-                    value::List::Untyped(v) => {
-                    let ser = serializer.serialize_seq(Some(v.len()))?;
+                }
+                value::List::Untyped(ref v) => {
+                    use ser::SerializeSeq;
+                    let mut seq = serializer.serialize_seq(Some(v.len()))?;
                     for e in v {
-                        ser.serialize_element(e)?;
+                        seq.serialize_element(e)?;
                     }
                     seq.end()
-                    }
                 }
-            }
+            },
             Value::Map(ref m) => {
-                Error("test".into())
+                use ser::SerializeMap;
+                let mut map = serializer.serialize_map(Some(m.len()))?;
+                for (k, v) in m.iter() {
+                    map.serialize_entry(k, v)?;
+                }
+                map.end()
             }
         }
     }
@@ -462,3 +703,15 @@ pub fn to_value<S: Serialize>(value: S) -> Result<Value, Error> {
     let mut serializer = Serializer::default();
     value.serialize(&mut serializer)
 }
+
+/// Like `to_value`, but any `shared::Shared` pointer that appears more than
+/// once is encoded only the first time; later occurrences become a
+/// `Value::Ref` back-reference. This makes `to_value` safe to use on shared
+/// or cyclic structures, provided the sharing is expressed with
+/// `shared::Shared` rather than a bare `Rc`/`Arc` — plain `Rc`/`Arc`
+/// `Serialize` impls forward straight to the pointee and erase the identity
+/// needed to notice a repeat (or a cycle) before it recurses forever.
+pub fn to_value_with_refs<S: Serialize>(value: S) -> Result<Value, Error> {
+    let mut serializer = Serializer::with_refs();
+    value.serialize(&mut serializer)
+}
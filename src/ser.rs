@@ -0,0 +1,676 @@
+use std::convert::TryFrom;
+use std::io::{self, Write};
+
+use serde::{ser, Serialize};
+
+use super::as_value::Error;
+use super::shared;
+use super::typed;
+
+fn io_err(err: io::Error) -> Error {
+    Error {
+        message: err.to_string(),
+    }
+}
+
+/// A struct type's class definition, as it has already been written to the
+/// wire: its Hessian type name and its fields in declared order. Two distinct
+/// Rust types can share a wire `name` (serde gives us no `TypeId` through the
+/// object-safe `Serializer` trait), so a cached definition is only reused
+/// when both `name` and `fields` match; a name collision with a different
+/// field list gets its own, separate class definition instead of corrupting
+/// the first one's.
+struct ClassDef {
+    // Owned rather than `&'static str`: `typed::Typed` needs to rename an
+    // already-registered definition to the wire name it was asked for, which
+    // isn't known until runtime.
+    name: String,
+    fields: Vec<&'static str>,
+}
+
+/// Streaming Hessian 2.0 serializer that writes straight into a `Write` sink
+/// as each `serialize_*` method is called, without ever allocating a `Value`
+/// tree. Struct fields and `Typed` values are the exception: their bytes are
+/// buffered until the header that precedes them can be written. Prefer this over `to_value` + a separate encode step for large
+/// payloads.
+///
+/// Encode-side only: the `'C'`/object-header class encoding this serializer
+/// writes for structs is not yet mirrored on the decode side. `de::Deserializer`
+/// reads `'C'`/`'O'`/compact object tags back into an untyped `Value::Map`
+/// (dropping the class name and definition table), so round-tripping a
+/// struct through `to_vec` + the deserializer loses its wire type name.
+/// Scoped out here rather than silently left unmentioned: reinstating it
+/// needs a class-definition table on the decode side symmetric to this one.
+pub struct Serializer<W> {
+    writer: W,
+    classes: Vec<ClassDef>,
+}
+
+impl<W: Write> Serializer<W> {
+    pub fn new(writer: W) -> Self {
+        Serializer {
+            writer,
+            classes: Vec::new(),
+        }
+    }
+
+    fn write_tag(&mut self, tag: u8) -> Result<(), Error> {
+        self.writer.write_all(&[tag]).map_err(io_err)
+    }
+
+    /// Encodes `value` into its own byte buffer instead of `self.writer`,
+    /// e.g. so a caller can inspect or relocate the bytes before committing
+    /// them. The class-definition table is threaded through the nested
+    /// serializer and merged back afterwards, so a struct type first seen
+    /// while buffering still gets the right definition index.
+    fn serialize_into_buffer<T: Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> Result<Vec<u8>, Error> {
+        let mut buf = Vec::new();
+        let mut sub = Serializer {
+            writer: &mut buf,
+            classes: std::mem::take(&mut self.classes),
+        };
+        let result = value.serialize(&mut sub);
+        self.classes = sub.classes;
+        result?;
+        Ok(buf)
+    }
+
+    /// Writes the header for an instance of the class at `index`: the
+    /// compact single-byte form for the first 16 definitions, `'O'` plus the
+    /// definition index otherwise.
+    fn write_object_header(&mut self, index: usize) -> Result<(), Error> {
+        if index < 16 {
+            self.write_tag(0x60 + index as u8)
+        } else {
+            self.write_tag(b'O')?;
+            self.write_i32(index as i32)
+        }
+    }
+
+    fn write_i32(&mut self, value: i32) -> Result<(), Error> {
+        self.write_tag(b'I')?;
+        self.writer.write_all(&value.to_be_bytes()).map_err(io_err)
+    }
+
+    fn write_i64(&mut self, value: i64) -> Result<(), Error> {
+        self.write_tag(b'L')?;
+        self.writer.write_all(&value.to_be_bytes()).map_err(io_err)
+    }
+
+    fn write_f64(&mut self, value: f64) -> Result<(), Error> {
+        self.write_tag(b'D')?;
+        self.writer.write_all(&value.to_be_bytes()).map_err(io_err)
+    }
+
+    fn write_string(&mut self, value: &str) -> Result<(), Error> {
+        self.write_tag(b'S')?;
+        let bytes = value.as_bytes();
+        self.writer
+            .write_all(&(bytes.len() as u16).to_be_bytes())
+            .map_err(io_err)?;
+        self.writer.write_all(bytes).map_err(io_err)
+    }
+
+    fn write_bytes(&mut self, value: &[u8]) -> Result<(), Error> {
+        self.write_tag(b'B')?;
+        self.writer
+            .write_all(&(value.len() as u16).to_be_bytes())
+            .map_err(io_err)?;
+        self.writer.write_all(value).map_err(io_err)
+    }
+}
+
+pub struct SeqSerializer<'a, W> {
+    ser: &'a mut Serializer<W>,
+}
+
+pub struct MapSerializer<'a, W> {
+    ser: &'a mut Serializer<W>,
+}
+
+pub struct StructSerializer<'a, W> {
+    ser: &'a mut Serializer<W>,
+    name: &'static str,
+    // Every field is buffered, not just on the first occurrence of `name`:
+    // whether this instance reuses an existing class definition depends on
+    // its full field list, which isn't known until every field has arrived.
+    fields: Vec<(&'static str, Vec<u8>)>,
+}
+
+impl<'a, W: Write> ser::SerializeSeq for SeqSerializer<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    #[inline]
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut *self.ser)
+    }
+
+    #[inline]
+    fn end(self) -> Result<(), Error> {
+        self.ser.write_tag(b'Z')
+    }
+}
+
+impl<'a, W: Write> ser::SerializeTuple for SeqSerializer<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    #[inline]
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    #[inline]
+    fn end(self) -> Result<(), Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl<'a, W: Write> ser::SerializeTupleStruct for SeqSerializer<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    #[inline]
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        ser::SerializeTuple::serialize_element(self, value)
+    }
+
+    #[inline]
+    fn end(self) -> Result<(), Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl<'a, W: Write> ser::SerializeTupleVariant for SeqSerializer<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    #[inline]
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        ser::SerializeTuple::serialize_element(self, value)
+    }
+
+    #[inline]
+    fn end(self) -> Result<(), Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+/// Collects the two fields of a `typed::Typed<T>` sentinel by buffering the
+/// value into its own small byte buffer, then re-emits it with `type_name`
+/// spliced in: right after the list/map tag, or as a struct's class name.
+/// Like `StructSerializer`, this step isn't fully streaming, since the tag (and, for a struct, the whole class definition) has to be
+/// known before the type name can be written.
+pub struct TypedSerializer<'a, W> {
+    ser: &'a mut Serializer<W>,
+    type_name: Option<String>,
+    bytes: Option<Vec<u8>>,
+}
+
+impl<'a, W: Write> ser::SerializeTupleVariant for TypedSerializer<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let buf = self.ser.serialize_into_buffer(value)?;
+        if self.type_name.is_none() {
+            let name = parse_written_string(&buf).ok_or_else(|| Error {
+                message: "Typed(..) expects a string type name".to_string(),
+            })?;
+            self.type_name = Some(name);
+        } else {
+            self.bytes = Some(buf);
+        }
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), Error> {
+        let name = self.type_name.ok_or_else(|| Error {
+            message: "Typed(..) is missing its type name field".to_string(),
+        })?;
+        let bytes = self.bytes.ok_or_else(|| Error {
+            message: "Typed(..) is missing its wrapped value field".to_string(),
+        })?;
+        match bytes.first() {
+            Some(b'V') | Some(b'M') => {
+                self.ser.write_tag(bytes[0])?;
+                self.ser.write_string(&name)?;
+                self.ser.writer.write_all(&bytes[1..]).map_err(io_err)
+            }
+            Some(b'C') => {
+                // A freshly-defined struct: splice `name` in as the class's
+                // wire name, then copy the field count, field names, object
+                // header, and values through unchanged. Safe because a
+                // leading 'C' means this is the definition's first (and
+                // only, so far) occurrence, so nothing else on the wire
+                // refers to it under its original name yet.
+                let rest = skip_written_string(&bytes[1..]).ok_or_else(|| Error {
+                    message: "Typed(..) could not parse the buffered class definition".to_string(),
+                })?;
+                self.ser.write_tag(b'C')?;
+                self.ser.write_string(&name)?;
+                self.ser.writer.write_all(rest).map_err(io_err)?;
+                if let Some(class) = self.ser.classes.last_mut() {
+                    class.name = name;
+                }
+                Ok(())
+            }
+            Some(b'O') | Some(0x60..=0x6f) => Err(Error {
+                message: format!(
+                    "Typed(\"{}\", ..) cannot rename a struct whose class was already defined \
+                     earlier in this document; wrap its first instance instead",
+                    name
+                ),
+            }),
+            _ => Err(Error {
+                message: format!(
+                    "Typed(\"{}\", ..) can only wrap a list, map, or struct value",
+                    name
+                ),
+            }),
+        }
+    }
+}
+
+/// Skips over the bytes written by `Serializer::write_string` (a `'S'` tag,
+/// a 2-byte big-endian length, and the utf8 payload) and returns whatever
+/// follows.
+fn skip_written_string(buf: &[u8]) -> Option<&[u8]> {
+    if buf.first() != Some(&b'S') || buf.len() < 3 {
+        return None;
+    }
+    let len = u16::from_be_bytes([buf[1], buf[2]]) as usize;
+    buf.get(3 + len..)
+}
+
+/// Recovers the `&str` written by `Serializer::write_string`, i.e. a `'S'`
+/// tag followed by a 2-byte big-endian length and the utf8 payload.
+fn parse_written_string(buf: &[u8]) -> Option<String> {
+    if buf.first() != Some(&b'S') || buf.len() < 3 {
+        return None;
+    }
+    let len = u16::from_be_bytes([buf[1], buf[2]]) as usize;
+    String::from_utf8(buf.get(3..3 + len)?.to_vec()).ok()
+}
+
+/// Either a plain tuple-variant payload or a `typed::Typed<T>` sentinel that
+/// was intercepted by `serialize_tuple_variant`.
+pub enum TupleVariantSerializer<'a, W> {
+    Seq(SeqSerializer<'a, W>),
+    Typed(TypedSerializer<'a, W>),
+}
+
+impl<'a, W: Write> ser::SerializeTupleVariant for TupleVariantSerializer<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        match self {
+            TupleVariantSerializer::Seq(s) => ser::SerializeTupleVariant::serialize_field(s, value),
+            TupleVariantSerializer::Typed(s) => s.serialize_field(value),
+        }
+    }
+
+    fn end(self) -> Result<(), Error> {
+        match self {
+            TupleVariantSerializer::Seq(s) => ser::SerializeTupleVariant::end(s),
+            TupleVariantSerializer::Typed(s) => s.end(),
+        }
+    }
+}
+
+impl<'a, W: Write> ser::SerializeMap for MapSerializer<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    #[inline]
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        key.serialize(&mut *self.ser)
+    }
+
+    #[inline]
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut *self.ser)
+    }
+
+    #[inline]
+    fn end(self) -> Result<(), Error> {
+        self.ser.write_tag(b'Z')
+    }
+}
+
+impl<'a, W: Write> ser::SerializeStruct for StructSerializer<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        let bytes = self.ser.serialize_into_buffer(value)?;
+        self.fields.push((key, bytes));
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), Error> {
+        let field_names: Vec<&'static str> = self.fields.iter().map(|(k, _)| *k).collect();
+        let index = match self
+            .ser
+            .classes
+            .iter()
+            .position(|c| c.name == self.name && c.fields == field_names)
+        {
+            Some(index) => index,
+            None => {
+                self.ser.write_tag(b'C')?;
+                self.ser.write_string(self.name)?;
+                self.ser.write_i32(field_names.len() as i32)?;
+                for field in &field_names {
+                    self.ser.write_string(field)?;
+                }
+                let index = self.ser.classes.len();
+                self.ser.classes.push(ClassDef {
+                    name: self.name.to_string(),
+                    fields: field_names,
+                });
+                index
+            }
+        };
+        self.ser.write_object_header(index)?;
+        for (_, bytes) in self.fields {
+            self.ser.writer.write_all(&bytes).map_err(io_err)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, W: Write> ser::SerializeStructVariant for StructSerializer<'a, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        ser::SerializeStruct::serialize_field(self, key, value)
+    }
+
+    #[inline]
+    fn end(self) -> Result<(), Error> {
+        ser::SerializeStruct::end(self)
+    }
+}
+
+impl<'a, W: Write> ser::Serializer for &'a mut Serializer<W> {
+    type Ok = ();
+    type Error = Error;
+
+    type SerializeSeq = SeqSerializer<'a, W>;
+    type SerializeTuple = Self::SerializeSeq;
+    type SerializeTupleStruct = Self::SerializeTuple;
+    type SerializeTupleVariant = TupleVariantSerializer<'a, W>;
+    type SerializeMap = MapSerializer<'a, W>;
+    type SerializeStruct = StructSerializer<'a, W>;
+    type SerializeStructVariant = Self::SerializeStruct;
+
+    #[inline]
+    fn serialize_bool(self, value: bool) -> Result<Self::Ok, Error> {
+        self.write_tag(if value { b'T' } else { b'F' })
+    }
+
+    #[inline]
+    fn serialize_i8(self, value: i8) -> Result<Self::Ok, Error> {
+        self.write_i32(value as i32)
+    }
+
+    #[inline]
+    fn serialize_i16(self, value: i16) -> Result<Self::Ok, Error> {
+        self.write_i32(value as i32)
+    }
+
+    #[inline]
+    fn serialize_i32(self, value: i32) -> Result<Self::Ok, Error> {
+        self.write_i32(value)
+    }
+
+    #[inline]
+    fn serialize_i64(self, value: i64) -> Result<Self::Ok, Error> {
+        self.write_i64(value)
+    }
+
+    #[inline]
+    fn serialize_i128(self, value: i128) -> Result<Self::Ok, Error> {
+        match i64::try_from(value) {
+            Ok(value) => self.write_i64(value),
+            Err(_) => Err(Error {
+                message: format!("i128 value {} does not fit in a Hessian Long", value),
+            }),
+        }
+    }
+
+    #[inline]
+    fn serialize_u8(self, value: u8) -> Result<Self::Ok, Error> {
+        self.write_i32(value as i32)
+    }
+
+    #[inline]
+    fn serialize_u16(self, value: u16) -> Result<Self::Ok, Error> {
+        self.write_i32(value as i32)
+    }
+
+    #[inline]
+    fn serialize_u32(self, value: u32) -> Result<Self::Ok, Error> {
+        if value < i32::max_value() as u32 {
+            self.write_i32(value as i32)
+        } else {
+            self.write_i64(value as i64)
+        }
+    }
+
+    #[inline]
+    fn serialize_u64(self, value: u64) -> Result<Self::Ok, Error> {
+        self.write_i64(value as i64)
+    }
+
+    #[inline]
+    fn serialize_u128(self, value: u128) -> Result<Self::Ok, Error> {
+        match i64::try_from(value) {
+            Ok(value) => self.write_i64(value),
+            Err(_) => Err(Error {
+                message: format!("u128 value {} does not fit in a Hessian Long", value),
+            }),
+        }
+    }
+
+    #[inline]
+    fn serialize_f32(self, value: f32) -> Result<Self::Ok, Error> {
+        self.write_f64(value as f64)
+    }
+
+    #[inline]
+    fn serialize_f64(self, value: f64) -> Result<Self::Ok, Error> {
+        self.write_f64(value)
+    }
+
+    #[inline]
+    fn serialize_char(self, value: char) -> Result<Self::Ok, Error> {
+        let mut buf = [0; 4];
+        self.write_string(value.encode_utf8(&mut buf))
+    }
+
+    #[inline]
+    fn serialize_str(self, value: &str) -> Result<Self::Ok, Error> {
+        self.write_string(value)
+    }
+
+    #[inline]
+    fn serialize_bytes(self, value: &[u8]) -> Result<Self::Ok, Error> {
+        self.write_bytes(value)
+    }
+
+    #[inline]
+    fn serialize_unit(self) -> Result<Self::Ok, Error> {
+        self.write_tag(b'N')
+    }
+
+    #[inline]
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Error> {
+        self.serialize_unit()
+    }
+
+    #[inline]
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Error> {
+        self.serialize_str(variant)
+    }
+
+    #[inline]
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Error> {
+        value.serialize(self)
+    }
+
+    #[inline]
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Error> {
+        value.serialize(self)
+    }
+
+    #[inline]
+    fn serialize_none(self) -> Result<Self::Ok, Error> {
+        self.serialize_unit()
+    }
+
+    #[inline]
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok, Error> {
+        value.serialize(self)
+    }
+
+    #[inline]
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        self.write_tag(b'V')?;
+        if let Some(len) = len {
+            self.write_i32(len as i32)?;
+        }
+        Ok(SeqSerializer { ser: self })
+    }
+
+    #[inline]
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Error> {
+        self.write_tag(b'V')?;
+        self.write_i32(len as i32)?;
+        Ok(SeqSerializer { ser: self })
+    }
+
+    #[inline]
+    fn serialize_tuple_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        self.write_tag(b'V')?;
+        self.write_string(name)?;
+        self.write_i32(len as i32)?;
+        Ok(SeqSerializer { ser: self })
+    }
+
+    #[inline]
+    fn serialize_tuple_variant(
+        self,
+        name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        if name == typed::ENUM_NAME && variant == typed::VARIANT_NAME && len == 2 {
+            return Ok(TupleVariantSerializer::Typed(TypedSerializer {
+                ser: self,
+                type_name: None,
+                bytes: None,
+            }));
+        }
+        if name == shared::ENUM_NAME && variant == shared::VARIANT_NAME {
+            // Unlike `as_value::Serializer`, this streaming writer has no ref
+            // table: it writes bytes directly and can't go back and patch an
+            // earlier position with a back-reference once a repeated `Rc` (or
+            // a true cycle) turns up partway through. Rather than silently
+            // emit the sentinel's literal name as a fake variant (or recurse
+            // forever on a cycle), refuse outright. Use `to_value_with_refs`
+            // for anything wrapped in `Shared`.
+            return Err(Error {
+                message: "Shared<T> is not supported by to_vec/to_writer; it requires ref \
+                    tracking that only to_value_with_refs provides"
+                    .to_string(),
+            });
+        }
+        self.write_tag(b'V')?;
+        self.write_string(variant)?;
+        self.write_i32(len as i32)?;
+        Ok(TupleVariantSerializer::Seq(SeqSerializer { ser: self }))
+    }
+
+    #[inline]
+    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        self.write_tag(b'M')?;
+        if let Some(len) = len {
+            self.write_i32(len as i32)?;
+        }
+        Ok(MapSerializer { ser: self })
+    }
+
+    #[inline]
+    fn serialize_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct, Error> {
+        Ok(StructSerializer {
+            ser: self,
+            name,
+            fields: Vec::with_capacity(len),
+        })
+    }
+
+    #[inline]
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        ser::Serializer::serialize_struct(self, variant, len)
+    }
+}
+
+/// Serializes `value` as Hessian 2.0 bytes, writing directly into a `Vec<u8>`
+/// without building an intermediate `Value` tree.
+pub fn to_vec<T: Serialize>(value: &T) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::new();
+    to_writer(&mut out, value)?;
+    Ok(out)
+}
+
+/// Serializes `value` as Hessian 2.0 bytes directly into `writer`.
+pub fn to_writer<W: Write, T: Serialize>(writer: W, value: &T) -> Result<(), Error> {
+    let mut serializer = Serializer::new(writer);
+    value.serialize(&mut serializer)
+}
@@ -0,0 +1,29 @@
+use std::rc::Rc;
+
+use serde::ser::{Serialize, SerializeTupleVariant, Serializer};
+
+/// Reserved enum/variant names used to smuggle a pointer address through the
+/// `Serialize` trait, the same sentinel-interception technique `typed` uses
+/// for type names. See `typed::ENUM_NAME` for the full rationale.
+pub(crate) const ENUM_NAME: &str = "$__hessian::Shared__$";
+pub(crate) const VARIANT_NAME: &str = "$__hessian::Shared__$";
+
+/// Wraps an `Rc<T>` so the serializer can key ref-tracking off its actual
+/// allocation instead of structural equality. Serializing the bare `Rc<T>`
+/// forwards straight to `T` and erases the pointer identity needed to
+/// recognize that two values are the *same* allocation (as opposed to two
+/// equal-by-value ones) — or to notice a cycle before it recurses forever.
+/// Wrap any `Rc` that may be reachable from more than one place in the value
+/// passed to `to_value_with_refs` with `Shared` to get a `Value::Ref`
+/// back-reference on repeat occurrences instead of infinite recursion.
+pub struct Shared<T: ?Sized>(pub Rc<T>);
+
+impl<T: Serialize + ?Sized> Serialize for Shared<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let ptr = Rc::as_ptr(&self.0) as *const () as u64;
+        let mut tv = serializer.serialize_tuple_variant(ENUM_NAME, 0, VARIANT_NAME, 2)?;
+        tv.serialize_field(&ptr)?;
+        tv.serialize_field(&*self.0)?;
+        tv.end()
+    }
+}
@@ -0,0 +1,31 @@
+use serde::ser::{Serialize, SerializeTupleVariant, Serializer};
+
+/// Reserved enum/variant names used to smuggle an out-of-band Hessian type
+/// name through the `Serialize` trait. Borrowed from ciborium's `@@TAG@@`
+/// technique: a specially-named tuple variant is intercepted by this crate's
+/// serializers instead of being encoded literally.
+pub(crate) const ENUM_NAME: &str = "$__hessian::Typed__$";
+pub(crate) const VARIANT_NAME: &str = "$__hessian::Typed__$";
+
+/// Wraps `value` so it is serialized as a Hessian typed list, map, or object
+/// carrying `type_name` on the wire, without requiring the Rust type itself
+/// to be renamed:
+///
+/// ```ignore
+/// Typed("com.example.Foo", value)
+/// ```
+///
+/// Through `to_value` this becomes `List::Typed` / a named `Map` carrying
+/// `type_name`, giving Java interop control over the wire type name. A
+/// struct written by `to_vec`/`to_writer` decodes into an untyped map, so its
+/// wire type name is not recovered on that path (see `Serializer` in `ser.rs`).
+pub struct Typed<T>(pub &'static str, pub T);
+
+impl<T: Serialize> Serialize for Typed<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tv = serializer.serialize_tuple_variant(ENUM_NAME, 0, VARIANT_NAME, 2)?;
+        tv.serialize_field(&self.0)?;
+        tv.serialize_field(&self.1)?;
+        tv.end()
+    }
+}
@@ -0,0 +1,170 @@
+use std::rc::Rc;
+
+use serde::ser::SerializeStruct;
+use serde::Serialize;
+
+use hessian_rs::shared::Shared;
+use hessian_rs::typed::Typed;
+use hessian_rs::{to_value, to_value_with_refs, to_vec, to_writer, Value};
+
+#[test]
+fn test_to_vec_matches_tagged_primitives() {
+    assert_eq!(to_vec(&true).unwrap(), vec![b'T']);
+    assert_eq!(to_vec(&false).unwrap(), vec![b'F']);
+
+    let mut expected = vec![b'I'];
+    expected.extend_from_slice(&42i32.to_be_bytes());
+    assert_eq!(to_vec(&42i32).unwrap(), expected);
+
+    let mut expected = vec![b'S', 0, 3];
+    expected.extend_from_slice(b"foo");
+    assert_eq!(to_vec(&"foo").unwrap(), expected);
+}
+
+#[test]
+fn test_to_writer_matches_to_vec() {
+    let mut buf = Vec::new();
+    to_writer(&mut buf, &123i64).unwrap();
+    assert_eq!(buf, to_vec(&123i64).unwrap());
+}
+
+#[test]
+fn test_i128_widens_to_long_within_range() {
+    assert_eq!(to_vec(&100i128).unwrap(), to_vec(&100i64).unwrap());
+}
+
+#[test]
+fn test_i128_overflow_is_an_error() {
+    let too_big = i128::from(i64::MAX) + 1;
+    assert!(to_vec(&too_big).is_err());
+}
+
+#[test]
+fn test_u128_overflow_is_an_error() {
+    let too_big = u128::from(u64::MAX);
+    assert!(to_vec(&too_big).is_err());
+}
+
+#[test]
+fn test_to_value_maps_scalars_through_serde() {
+    assert_eq!(to_value(42i32).unwrap(), Value::Int(42));
+    assert_eq!(to_value(42i64).unwrap(), Value::Long(42));
+    assert_eq!(to_value(3.5f64).unwrap(), Value::Double(3.5));
+    assert_eq!(to_value("foo").unwrap(), Value::String("foo".to_string()));
+    assert_eq!(to_value(true).unwrap(), Value::Bool(true));
+}
+
+#[test]
+fn test_typed_wraps_a_list_on_the_wire() {
+    let name = "com.example.IntList";
+    let bytes = to_vec(&Typed(name, vec![1i32, 2, 3])).unwrap();
+
+    let mut expected = vec![b'V', b'S'];
+    expected.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    expected.extend_from_slice(name.as_bytes());
+    expected.push(b'I');
+    expected.extend_from_slice(&3i32.to_be_bytes());
+    for n in 1..=3i32 {
+        expected.push(b'I');
+        expected.extend_from_slice(&n.to_be_bytes());
+    }
+    expected.push(b'Z');
+
+    assert_eq!(bytes, expected);
+}
+
+#[test]
+fn test_shared_dedups_a_repeated_rc_with_refs() {
+    let shared = Rc::new(5i32);
+    let pair = vec![Shared(shared.clone()), Shared(shared)];
+
+    let value = to_value_with_refs(pair).unwrap();
+
+    // `Value` doesn't expose its list internals for pattern matching from
+    // outside the crate, but it does derive `Debug`: the second occurrence
+    // of the same `Rc` must come back as a `Value::Ref`, not a second
+    // `Value::Int(5)`.
+    assert!(format!("{:?}", value).contains("Ref"));
+}
+
+#[test]
+fn test_shared_without_refs_serializes_the_pointee_twice() {
+    let shared = Rc::new(5i32);
+    let pair = vec![Shared(shared.clone()), Shared(shared)];
+
+    let value = to_value(pair).unwrap();
+
+    assert!(!format!("{:?}", value).contains("Ref"));
+}
+
+#[test]
+fn test_shared_is_rejected_by_the_streaming_serializer() {
+    // `to_vec`/`to_writer` have no ref table to dedup a repeated `Rc` or
+    // break a cycle, unlike `to_value_with_refs`. Rather than write the raw
+    // sentinel onto the wire or recurse forever on a cycle, this must error.
+    let shared = Rc::new(5i32);
+
+    assert!(to_vec(&Shared(shared)).is_err());
+}
+
+struct GeoPoint {
+    x: i32,
+    y: i32,
+}
+
+impl Serialize for GeoPoint {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("Point", 2)?;
+        s.serialize_field("x", &self.x)?;
+        s.serialize_field("y", &self.y)?;
+        s.end()
+    }
+}
+
+struct UiPoint {
+    label: String,
+}
+
+impl Serialize for UiPoint {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("Point", 1)?;
+        s.serialize_field("label", &self.label)?;
+        s.end()
+    }
+}
+
+#[test]
+fn test_distinct_struct_types_sharing_a_wire_name_get_separate_class_defs() {
+    // Regression test: these two unrelated Rust types both use the wire name
+    // "Point" but have different fields. Serializing them in the same
+    // document used to let the second one's class lookup match the first's
+    // by name alone and reuse its definition, corrupting the field layout.
+    let geo = GeoPoint { x: 1, y: 2 };
+    let ui = UiPoint {
+        label: "ok".to_string(),
+    };
+
+    let bytes = to_vec(&(geo, ui)).unwrap();
+
+    assert_eq!(bytes.iter().filter(|&&b| b == b'C').count(), 2);
+}
+
+#[test]
+fn test_to_value_keeps_distinct_struct_types_sharing_a_wire_name_separate() {
+    // Same scenario as the `to_vec` regression test above, but through
+    // `to_value`: there's no class-definition table on this path to corrupt
+    // (see the comment on the `SerializeStruct` impl for `StructSerializer`
+    // in `as_value.rs`), but each
+    // instance's own name and fields must still come back intact.
+    let geo = GeoPoint { x: 1, y: 2 };
+    let ui = UiPoint {
+        label: "ok".to_string(),
+    };
+
+    let geo_value = to_value(geo).unwrap();
+    let ui_value = to_value(ui).unwrap();
+
+    assert!(matches!(geo_value, Value::Map(_)));
+    assert!(matches!(ui_value, Value::Map(_)));
+    assert_ne!(geo_value, ui_value);
+}